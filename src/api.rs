@@ -1,19 +1,26 @@
 use axum::{
     Extension, Json, Router,
     extract::{Path, State},
+    http::{HeaderMap, StatusCode},
     middleware,
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use std::sync::Arc;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer};
 use tracing::Level;
 use uuid::Uuid;
 
 use crate::config::Config;
 use crate::error::{
-    ApiError, ApiErrorWithMeta, E_BAD_AMOUNT, E_DB_FAILURE, E_PROCESS_FAILURE, E_PURCHASE_CONFLICT,
+    ApiError, ApiErrorWithMeta, E_ALREADY_REFERRED, E_BAD_AMOUNT, E_DB_FAILURE,
+    E_IDEMPOTENCY_MISMATCH, E_PROCESS_FAILURE, E_PURCHASE_CONFLICT, E_REFERRAL_CODE_NOT_FOUND,
+    E_REFERRAL_CYCLE, E_USER_NOT_FOUND,
 };
+use crate::events::{Event, Producer};
 use crate::process_purchase;
 use crate::responses::{ApiOk, RequestMeta, meta_middleware};
 
@@ -24,6 +31,8 @@ pub struct AppState {
     pub pool: PgPool,
     /// The application configuration.
     pub config: Config,
+    /// The event producer events are published through after each commit.
+    pub producer: Arc<dyn Producer>,
 }
 
 /// The request to create a new purchase.
@@ -62,12 +71,60 @@ pub struct ProcessResponse {
     pub processed: Uuid,
 }
 
+/// The response after reconciling a user's cached balance against the
+/// ledger-derived balance.
+#[derive(Serialize)]
+pub struct ReconcileBalanceResponse {
+    /// The ID of the user.
+    pub user_id: i64,
+    /// The balance previously cached in the `balances` table.
+    pub cached_balance: i64,
+    /// The balance recomputed from the `balances_v` ledger view.
+    pub recomputed_balance: i64,
+}
+
+/// The request to mint a new referral code for a user.
+#[derive(Deserialize)]
+pub struct CreateReferralCodeRequest {
+    /// The ID of the user the code will credit as referrer.
+    pub user_id: i64,
+}
+
+/// The response after minting a new referral code.
+#[derive(Serialize)]
+pub struct CreateReferralCodeResponse {
+    /// The generated referral code.
+    pub code: String,
+}
+
+/// The request to redeem a referral code during signup.
+#[derive(Deserialize)]
+pub struct SignupRequest {
+    /// The ID of the user being linked into the referral graph.
+    pub new_user_id: i64,
+    /// The referral code being redeemed.
+    pub code: String,
+}
+
+/// The response after redeeming a referral code.
+#[derive(Serialize)]
+pub struct SignupResponse {
+    /// The ID of the referrer the new user was linked to.
+    pub referrer_id: i64,
+}
+
 pub fn init_router(state: AppState) -> Router {
     Router::new()
         .route("/health", get(|| async { "ok" }))
         .route("/balances/{user_id}", get(get_balance_handler))
+        .route(
+            "/balances/{user_id}/reconcile",
+            post(reconcile_balance_handler),
+        )
         .route("/purchases", post(create_purchase_handler))
         .route("/process/{id}", post(process_purchase_handler))
+        .route("/referral-codes", post(create_referral_code_handler))
+        .route("/signup", post(signup_handler))
         .with_state(state)
         .layer(
             TraceLayer::new_for_http()
@@ -84,7 +141,7 @@ async fn get_balance_handler(
     Extension(meta): Extension<RequestMeta>,
 ) -> Result<ApiOk<BalanceResponse>, ApiErrorWithMeta> {
     let row = sqlx::query!(
-        r#"SELECT balance FROM balances WHERE user_id = $1"#,
+        r#"SELECT balance AS "balance!" FROM balances_v WHERE user_id = $1"#,
         user_id
     )
     .fetch_optional(&st.pool)
@@ -104,11 +161,109 @@ async fn get_balance_handler(
     ))
 }
 
+/// Recomputes a user's balance from the `balances_v` ledger view and
+/// refreshes the cached row in `balances` to match, returning both values so
+/// operators can detect and repair drift between the cache and the ledger.
+async fn reconcile_balance_handler(
+    State(st): State<AppState>,
+    Path(user_id): Path<i64>,
+    Extension(meta): Extension<RequestMeta>,
+) -> Result<ApiOk<ReconcileBalanceResponse>, ApiErrorWithMeta> {
+    let mut tx = st.pool.begin().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    let cached_row = sqlx::query!(
+        r#"SELECT balance FROM balances WHERE user_id = $1 FOR UPDATE"#,
+        user_id
+    )
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+    let cached_balance: i64 = cached_row.map(|r| r.balance).unwrap_or(0);
+
+    let recomputed_row = sqlx::query!(
+        r#"SELECT balance AS "balance!" FROM balances_v WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+    let recomputed_balance: i64 = recomputed_row.map(|r| r.balance).unwrap_or(0);
+
+    sqlx::query!(
+        r#"INSERT INTO balances (user_id, balance) VALUES ($1, $2)
+           ON CONFLICT (user_id) DO UPDATE SET balance = EXCLUDED.balance"#,
+        user_id,
+        recomputed_balance
+    )
+    .execute(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    tx.commit().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    Ok(ApiOk::ok(
+        "balance reconciled",
+        ReconcileBalanceResponse {
+            user_id,
+            cached_balance,
+            recomputed_balance,
+        },
+        meta,
+    ))
+}
+
+/// Claims an idempotency key and, if no one else holds it, inserts the
+/// purchase in the same transaction as the claim. The claim is a unique
+/// insert on `idempotency_keys.key`: a concurrent request for the same key
+/// blocks on that insert until this transaction commits or rolls back, so
+/// two requests racing on the same key (and no client-supplied `id`) can
+/// never both mint a purchase row.
 async fn create_purchase_handler(
     State(st): State<AppState>,
     Extension(meta): Extension<RequestMeta>,
+    headers: HeaderMap,
     Json(req): Json<CreatePurchaseRequest>,
-) -> Result<ApiOk<CreatePurchaseResponse>, ApiErrorWithMeta> {
+) -> Result<Response, ApiErrorWithMeta> {
+    let idempotency_key = idempotency_key(&headers);
+    let request_hash = hash_request(&req);
+
+    let mut tx = st.pool.begin().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    if let Some(key) = &idempotency_key {
+        if let Some(resp) = claim_idempotency_key(&mut tx, key, &request_hash, &meta).await? {
+            tx.commit().await.map_err(|e| {
+                ApiError::Internal(e.into())
+                    .with_meta(meta.clone())
+                    .with_code(E_DB_FAILURE)
+            })?;
+            return Ok(resp);
+        }
+    }
+
     let id = req.id.unwrap_or_else(Uuid::new_v4);
 
     if req.amount < 0 {
@@ -124,7 +279,7 @@ async fn create_purchase_handler(
         req.amount,
         req.status
     )
-    .execute(&st.pool)
+    .execute(tx.as_mut())
     .await
     .map_err(|e| {
         if let sqlx::Error::Database(db_err) = &e {
@@ -139,27 +294,399 @@ async fn create_purchase_handler(
             .with_code(E_DB_FAILURE)
     })?;
 
-    Ok(ApiOk::created(
-        "purchase created",
-        CreatePurchaseResponse { id },
-        meta,
-    ))
+    let data = CreatePurchaseResponse { id };
+
+    if let Some(key) = &idempotency_key {
+        let envelope = serde_json::json!({
+            "message": "purchase created",
+            "data": &data,
+            "meta": &meta,
+        });
+        finish_idempotency_key(&mut tx, key, StatusCode::CREATED.as_u16(), &envelope, &meta)
+            .await?;
+    }
+
+    tx.commit().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    if let Err(e) = st
+        .producer
+        .publish(Event::PurchaseCreated {
+            purchase_id: id,
+            request_id: meta.request_id.clone(),
+        })
+        .await
+    {
+        tracing::warn!("failed to publish event: {e:?}");
+    }
+
+    Ok(ApiOk::created("purchase created", data, meta).into_response())
 }
 
 async fn process_purchase_handler(
     State(st): State<AppState>,
     Path(id): Path<Uuid>,
     Extension(meta): Extension<RequestMeta>,
-) -> Result<ApiOk<ProcessResponse>, ApiErrorWithMeta> {
-    process_purchase(&st.pool, id).await.map_err(|e| {
-        ApiError::Internal(e)
+    headers: HeaderMap,
+) -> Result<Response, ApiErrorWithMeta> {
+    let idempotency_key = idempotency_key(&headers);
+    let request_hash = hash_request(&id);
+
+    // The claim, if any, is held in an open transaction for the rest of this
+    // handler rather than committed up front. We only commit it (persisting
+    // the claim and its result together) once `process_purchase` has
+    // actually succeeded; on failure the transaction is rolled back, so the
+    // key is freed and a retry can claim it again instead of forever
+    // replaying a premature `status=0` response.
+    let Some(key) = idempotency_key.as_deref() else {
+        process_purchase(
+            &st.pool,
+            st.producer.as_ref(),
+            &st.config.reward_percentages,
+            st.config.l1_signup_bonus,
+            &meta.request_id,
+            id,
+        )
+        .await
+        .map_err(|e| {
+            ApiError::Internal(e)
+                .with_meta(meta.clone())
+                .with_code(E_PROCESS_FAILURE)
+        })?;
+        return Ok(ApiOk::ok("purchase processed", ProcessResponse { processed: id }, meta)
+            .into_response());
+    };
+
+    let mut tx = st.pool.begin().await.map_err(|e| {
+        ApiError::Internal(e.into())
             .with_meta(meta.clone())
-            .with_code(E_PROCESS_FAILURE)
+            .with_code(E_DB_FAILURE)
     })?;
 
-    Ok(ApiOk::ok(
-        "purchase processed",
-        ProcessResponse { processed: id },
+    if let Some(resp) = claim_idempotency_key(&mut tx, key, &request_hash, &meta).await? {
+        tx.commit().await.map_err(|e| {
+            ApiError::Internal(e.into())
+                .with_meta(meta.clone())
+                .with_code(E_DB_FAILURE)
+        })?;
+        return Ok(resp);
+    }
+
+    let process_result = process_purchase(
+        &st.pool,
+        st.producer.as_ref(),
+        &st.config.reward_percentages,
+        st.config.l1_signup_bonus,
+        &meta.request_id,
+        id,
+    )
+    .await;
+
+    let Err(process_err) = process_result else {
+        let data = ProcessResponse { processed: id };
+        let envelope = serde_json::json!({
+            "message": "purchase processed",
+            "data": &data,
+            "meta": &meta,
+        });
+        finish_idempotency_key(&mut tx, key, StatusCode::OK.as_u16(), &envelope, &meta).await?;
+        tx.commit().await.map_err(|e| {
+            ApiError::Internal(e.into())
+                .with_meta(meta.clone())
+                .with_code(E_DB_FAILURE)
+        })?;
+        return Ok(ApiOk::ok("purchase processed", data, meta).into_response());
+    };
+
+    tx.rollback().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    Err(ApiError::Internal(process_err)
+        .with_meta(meta.clone())
+        .with_code(E_PROCESS_FAILURE))
+}
+
+/// Reads the `Idempotency-Key` header, if present.
+fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("Idempotency-Key")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+/// Hashes a request payload so a repeated idempotency key can be checked
+/// against the body it was first used with.
+fn hash_request<T: Serialize>(req: &T) -> String {
+    let bytes = serde_json::to_vec(req).unwrap_or_default();
+    format!("{:x}", Sha256::digest(&bytes))
+}
+
+/// Claims an idempotency key inside `tx` via a unique insert. Returns
+/// `Ok(None)` when the caller now holds the claim and should execute the
+/// guarded effect in the same transaction, then call
+/// `finish_idempotency_key`. Returns `Ok(Some(response))` to replay a
+/// previously stored response when the key was already claimed (this blocks
+/// until the original claimant's transaction commits or rolls back, so the
+/// replayed response, once available, always reflects a finished attempt).
+/// Returns an `E_IDEMPOTENCY_MISMATCH` conflict if the key was reused with a
+/// different request body.
+async fn claim_idempotency_key(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    key: &str,
+    request_hash: &str,
+    meta: &RequestMeta,
+) -> Result<Option<Response>, ApiErrorWithMeta> {
+    let claimed = sqlx::query!(
+        r#"INSERT INTO idempotency_keys (key, request_hash, status, response_body)
+           VALUES ($1, $2, 0, 'null'::jsonb)
+           ON CONFLICT (key) DO NOTHING"#,
+        key,
+        request_hash
+    )
+    .execute(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    if claimed.rows_affected() == 1 {
+        return Ok(None);
+    }
+
+    let row = sqlx::query!(
+        r#"SELECT request_hash, response_body, status FROM idempotency_keys WHERE key = $1"#,
+        key
+    )
+    .fetch_one(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    if row.request_hash != request_hash {
+        return Err(ApiError::Conflict(
+            "idempotency key reused with a different request body".into(),
+        )
+        .with_meta(meta.clone())
+        .with_code(E_IDEMPOTENCY_MISMATCH));
+    }
+
+    let status = StatusCode::from_u16(row.status as u16).unwrap_or(StatusCode::OK);
+    Ok(Some((status, Json(row.response_body)).into_response()))
+}
+
+/// Records the outcome of a claimed idempotency key, in the same
+/// transaction as the claim, so a retried request never observes a claim
+/// without a matching result.
+async fn finish_idempotency_key(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    key: &str,
+    status: u16,
+    response_body: &serde_json::Value,
+    meta: &RequestMeta,
+) -> Result<(), ApiErrorWithMeta> {
+    sqlx::query!(
+        r#"UPDATE idempotency_keys SET status = $1, response_body = $2 WHERE key = $3"#,
+        status as i32,
+        response_body,
+        key
+    )
+    .execute(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+    Ok(())
+}
+
+/// Generates a unique short referral code owned by `user_id`. Retries on a
+/// code collision, same idempotency-guard pattern as `insert_reward`.
+async fn create_referral_code_handler(
+    State(st): State<AppState>,
+    Extension(meta): Extension<RequestMeta>,
+    Json(req): Json<CreateReferralCodeRequest>,
+) -> Result<ApiOk<CreateReferralCodeResponse>, ApiErrorWithMeta> {
+    loop {
+        let code = generate_referral_code();
+        let res = sqlx::query!(
+            r#"INSERT INTO referral_codes (user_id, code) VALUES ($1, $2)
+               ON CONFLICT (code) DO NOTHING"#,
+            req.user_id,
+            code
+        )
+        .execute(&st.pool)
+        .await
+        .map_err(|e| {
+            ApiError::Internal(e.into())
+                .with_meta(meta.clone())
+                .with_code(E_DB_FAILURE)
+        })?;
+
+        if res.rows_affected() == 1 {
+            return Ok(ApiOk::created(
+                "referral code created",
+                CreateReferralCodeResponse { code },
+                meta,
+            ));
+        }
+    }
+}
+
+fn generate_referral_code() -> String {
+    Uuid::new_v4().simple().to_string()[..8].to_ascii_uppercase()
+}
+
+/// Resolves a referral code to its owner and links `new_user_id` to that
+/// owner as referrer, inside one transaction. Rejects the redemption if the
+/// new user already has a referrer, the code is unknown, or linking would
+/// create a cycle in the referrer graph (the code owner is already a
+/// descendant of the new user).
+async fn signup_handler(
+    State(st): State<AppState>,
+    Extension(meta): Extension<RequestMeta>,
+    Json(req): Json<SignupRequest>,
+) -> Result<ApiOk<SignupResponse>, ApiErrorWithMeta> {
+    let mut tx = st.pool.begin().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    let code_row = sqlx::query!(
+        r#"SELECT user_id FROM referral_codes WHERE code = $1"#,
+        req.code
+    )
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    let Some(code_row) = code_row else {
+        return Err(ApiError::BadRequest("unknown referral code".into())
+            .with_meta(meta)
+            .with_code(E_REFERRAL_CODE_NOT_FOUND));
+    };
+    let owner_id = code_row.user_id;
+
+    let user_row = sqlx::query!(
+        r#"SELECT referrer_id FROM users WHERE id = $1 FOR UPDATE"#,
+        req.new_user_id
+    )
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    let Some(user_row) = user_row else {
+        return Err(ApiError::BadRequest("unknown user".into())
+            .with_meta(meta)
+            .with_code(E_USER_NOT_FOUND));
+    };
+
+    if user_row.referrer_id.is_some() {
+        return Err(ApiError::Conflict("user already has a referrer".into())
+            .with_meta(meta)
+            .with_code(E_ALREADY_REFERRED));
+    }
+
+    let cycle = creates_cycle(&mut tx, req.new_user_id, owner_id)
+        .await
+        .map_err(|e| {
+            ApiError::Internal(e)
+                .with_meta(meta.clone())
+                .with_code(E_DB_FAILURE)
+        })?;
+    if cycle {
+        return Err(ApiError::Conflict("referral would create a cycle".into())
+            .with_meta(meta)
+            .with_code(E_REFERRAL_CYCLE));
+    }
+
+    sqlx::query!(
+        r#"UPDATE users SET referrer_id = $1 WHERE id = $2"#,
+        owner_id,
+        req.new_user_id
+    )
+    .execute(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    sqlx::query!(
+        r#"INSERT INTO referrals (user_id, referrer_id) VALUES ($1, $2)"#,
+        req.new_user_id,
+        owner_id
+    )
+    .execute(tx.as_mut())
+    .await
+    .map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    tx.commit().await.map_err(|e| {
+        ApiError::Internal(e.into())
+            .with_meta(meta.clone())
+            .with_code(E_DB_FAILURE)
+    })?;
+
+    Ok(ApiOk::created(
+        "signup linked",
+        SignupResponse {
+            referrer_id: owner_id,
+        },
         meta,
     ))
 }
+
+/// Walks up the referrer chain from `owner_id`; returns true if `new_user_id`
+/// is found, which would mean setting `new_user_id`'s referrer to `owner_id`
+/// closes a loop in the referrer graph. Each row is read with `FOR UPDATE`
+/// so a concurrent signup cannot repoint a referrer mid-walk and slip a
+/// cycle past a traversal that read stale data; combined with the caller's
+/// own `FOR UPDATE` lock on `new_user_id`, the whole chain relevant to this
+/// signup is locked for the rest of the transaction.
+async fn creates_cycle(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    new_user_id: i64,
+    owner_id: i64,
+) -> anyhow::Result<bool> {
+    let mut current = owner_id;
+    loop {
+        if current == new_user_id {
+            return Ok(true);
+        }
+        let row = sqlx::query!(
+            r#"SELECT referrer_id FROM users WHERE id = $1 FOR UPDATE"#,
+            current
+        )
+        .fetch_optional(tx.as_mut())
+        .await?;
+        match row.and_then(|r| r.referrer_id) {
+            Some(r) => current = r,
+            None => return Ok(false),
+        }
+    }
+}
@@ -4,12 +4,31 @@ use serde::Deserialize;
 pub struct Config {
     pub server_port: u16,
     pub database_url: String,
+    /// The reward percentage paid at each level of the referrer chain, e.g.
+    /// `[10, 5, 2]` pays 10% to the direct referrer, 5% to their referrer,
+    /// and 2% to the referrer above that. The chain is walked at most
+    /// `reward_percentages.len()` levels deep.
+    pub reward_percentages: Vec<i32>,
+    /// The one-time bonus credited to a referred user's own balance when
+    /// their first purchase is captured. Zero disables the bonus.
+    #[serde(default)]
+    pub l1_signup_bonus: i64,
+    /// The Kafka broker(s) to publish events to. Events are dropped if unset.
+    #[serde(default)]
+    pub event_broker_url: Option<String>,
+    /// The Kafka topic to publish events to.
+    #[serde(default)]
+    pub event_topic: Option<String>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, config::ConfigError> {
         let config = config::Config::builder()
-            .add_source(config::Environment::default())
+            .add_source(
+                config::Environment::default()
+                    .list_separator(",")
+                    .with_list_parse_key("reward_percentages"),
+            )
             .build()?;
         config.try_deserialize()
     }
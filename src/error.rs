@@ -12,6 +12,11 @@ pub const E_BAD_AMOUNT: &str = "BAD_AMOUNT";
 pub const E_DB_FAILURE: &str = "DB_FAILURE";
 pub const E_PURCHASE_CONFLICT: &str = "PURCHASE_CONFLICT";
 pub const E_PROCESS_FAILURE: &str = "PROCESS_FAILURE";
+pub const E_REFERRAL_CODE_NOT_FOUND: &str = "REFERRAL_CODE_NOT_FOUND";
+pub const E_USER_NOT_FOUND: &str = "USER_NOT_FOUND";
+pub const E_ALREADY_REFERRED: &str = "ALREADY_REFERRED";
+pub const E_REFERRAL_CYCLE: &str = "REFERRAL_CYCLE";
+pub const E_IDEMPOTENCY_MISMATCH: &str = "IDEMPOTENCY_MISMATCH";
 
 #[derive(Debug)]
 pub enum ApiError {
@@ -0,0 +1,110 @@
+//! Event-streaming subsystem. Each state change in `process_purchase` and
+//! `create_purchase_handler` is published as a structured [`Event`] once its
+//! database transaction has committed, so consumers never observe an event
+//! for a change that was rolled back.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// A structured event emitted after a state change commits.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    PurchaseCreated {
+        purchase_id: Uuid,
+        request_id: String,
+    },
+    RewardGranted {
+        purchase_id: Uuid,
+        beneficiary_user_id: i64,
+        level: i32,
+        amount: i64,
+        request_id: String,
+    },
+    RewardReversed {
+        purchase_id: Uuid,
+        beneficiary_user_id: i64,
+        amount: i64,
+        request_id: String,
+    },
+    SignupBonusGranted {
+        purchase_id: Uuid,
+        user_id: i64,
+        amount: i64,
+        request_id: String,
+    },
+}
+
+/// Publishes events to downstream consumers.
+#[async_trait]
+pub trait Producer: Send + Sync {
+    async fn publish(&self, event: Event) -> Result<()>;
+}
+
+/// A producer that drops every event. Used when no broker is configured, so
+/// the crate still builds and runs without Kafka.
+pub struct NoopProducer;
+
+#[async_trait]
+impl Producer for NoopProducer {
+    async fn publish(&self, _event: Event) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub mod kafka {
+    use super::{Event, Producer};
+    use anyhow::{Context, Result};
+    use async_trait::async_trait;
+    use rdkafka::ClientConfig;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use std::time::Duration;
+
+    /// Publishes events to a Kafka topic via `rdkafka`.
+    pub struct KafkaProducer {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl KafkaProducer {
+        pub fn new(broker_url: &str, topic: impl Into<String>) -> Result<Self> {
+            let producer: FutureProducer = ClientConfig::new()
+                .set("bootstrap.servers", broker_url)
+                .create()
+                .context("failed to create Kafka producer")?;
+            Ok(Self {
+                producer,
+                topic: topic.into(),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Producer for KafkaProducer {
+        async fn publish(&self, event: Event) -> Result<()> {
+            let payload = serde_json::to_vec(&event).context("failed to serialize event")?;
+            let key = event_key(&event);
+            self.producer
+                .send(
+                    FutureRecord::to(&self.topic).payload(&payload).key(&key),
+                    Duration::from_secs(5),
+                )
+                .await
+                .map_err(|(e, _)| e)
+                .context("failed to publish event to Kafka")?;
+            Ok(())
+        }
+    }
+
+    fn event_key(event: &Event) -> String {
+        match event {
+            Event::PurchaseCreated { purchase_id, .. }
+            | Event::RewardGranted { purchase_id, .. }
+            | Event::RewardReversed { purchase_id, .. }
+            | Event::SignupBonusGranted { purchase_id, .. } => purchase_id.to_string(),
+        }
+    }
+}
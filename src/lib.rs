@@ -2,25 +2,26 @@
 
 mod api;
 mod error;
+mod events;
 mod responses;
 mod types;
 
 use anyhow::Context;
 use anyhow::Result;
 pub use api::init_router;
+#[cfg(feature = "kafka")]
+pub use events::kafka::KafkaProducer;
+pub use events::{Event, NoopProducer, Producer};
 use sqlx::{PgPool, Postgres, Transaction, postgres::PgPoolOptions};
+use std::collections::HashSet;
 pub use types::{AppState, Referral, ReferralCode};
+use tracing::warn;
 use uuid::Uuid;
 
-/// The percentage for the first level referrer.
-pub const L1_PERCENTAGE: i32 = 10;
-/// The percentage for the second level referrer.
-pub const L2_PERCENTAGE: i32 = 5;
-
 // const PAYMENT_STATUS_AUTHORIZED: &str = "authorized";
 const PAYMENT_STATUS_CAPTURED: &str = "captured";
-// const PAYMENT_STATUS_REFUNDED: &str = "refunded";
-// const PAYMENT_STATUS_VOIDED: &str = "voided";
+const PAYMENT_STATUS_REFUNDED: &str = "refunded";
+const PAYMENT_STATUS_VOIDED: &str = "voided";
 
 /// Initializes the database pool.
 pub async fn init_pool() -> Result<PgPool> {
@@ -33,9 +34,21 @@ pub async fn init_pool() -> Result<PgPool> {
     Ok(pool)
 }
 
-/// Processes a purchase and distributes the rewards to the referrers.
-pub async fn process_purchase(pool: &PgPool, purchase_id: Uuid) -> Result<()> {
+/// Processes a purchase: distributes rewards on capture, or claws back
+/// previously-distributed rewards on refund/void. Re-reads the purchase's
+/// current status on every call, so a payment provider webhook that flips
+/// the status from `captured` to `refunded`/`voided` and calls this again is
+/// enough to keep balances correct.
+pub async fn process_purchase(
+    pool: &PgPool,
+    producer: &dyn Producer,
+    reward_percentages: &[i32],
+    l1_signup_bonus: i64,
+    request_id: &str,
+    purchase_id: Uuid,
+) -> Result<()> {
     let mut tx = pool.begin().await?;
+    let mut events = Vec::new();
 
     let rec = sqlx::query!(
         r#"SELECT id, user_id, amount, status FROM purchases WHERE id = $1 FOR UPDATE"#,
@@ -44,34 +57,173 @@ pub async fn process_purchase(pool: &PgPool, purchase_id: Uuid) -> Result<()> {
     .fetch_one(tx.as_mut())
     .await?;
 
-    if rec.status.as_str() != PAYMENT_STATUS_CAPTURED {
-        tx.commit().await?;
-        return Ok(());
+    match rec.status.as_str() {
+        PAYMENT_STATUS_CAPTURED => {
+            distribute_rewards(
+                &mut tx,
+                &mut events,
+                reward_percentages,
+                purchase_id,
+                rec.user_id,
+                rec.amount,
+                request_id,
+            )
+            .await?;
+            grant_signup_bonus(
+                &mut tx,
+                &mut events,
+                purchase_id,
+                rec.user_id,
+                l1_signup_bonus,
+                request_id,
+            )
+            .await?;
+        }
+        PAYMENT_STATUS_REFUNDED | PAYMENT_STATUS_VOIDED => {
+            claw_back_rewards(&mut tx, &mut events, purchase_id, request_id).await?;
+        }
+        _ => {}
     }
 
-    let buyer_id: i64 = rec.user_id;
-    let amount: i64 = rec.amount;
-
-    let l1 = active_referrer(&mut tx, buyer_id).await?;
-    let l2 = match l1 {
-        Some(u) => active_referrer(&mut tx, u).await?,
-        None => None,
-    };
+    tx.commit().await?;
 
-    if let Some(u1) = l1 {
-        let amt = percent_of(amount, L1_PERCENTAGE);
-        if amt > 0 && insert_reward(&mut tx, purchase_id, buyer_id, u1, 1, amt).await? {
-            add_balance(&mut tx, u1, amt).await?;
+    for event in events {
+        if let Err(e) = producer.publish(event).await {
+            warn!("failed to publish event: {e:?}");
         }
     }
-    if let Some(u2) = l2 {
-        let amt = percent_of(amount, L2_PERCENTAGE);
-        if amt > 0 && insert_reward(&mut tx, purchase_id, buyer_id, u2, 2, amt).await? {
-            add_balance(&mut tx, u2, amt).await?;
+
+    Ok(())
+}
+
+/// Walks up the referrer chain from `buyer_id`, paying out a reward at each
+/// active referrer up to `reward_percentages.len()` levels deep. Inactive
+/// referrers are skipped (no reward, no level consumed) but the walk
+/// continues past them up the chain. A `visited` set guards against a cycle
+/// in the referrer graph paying out indefinitely.
+async fn distribute_rewards(
+    tx: &mut Transaction<'_, Postgres>,
+    events: &mut Vec<Event>,
+    reward_percentages: &[i32],
+    purchase_id: Uuid,
+    buyer_id: i64,
+    amount: i64,
+    request_id: &str,
+) -> Result<()> {
+    let mut visited = HashSet::new();
+    visited.insert(buyer_id);
+
+    let mut current = buyer_id;
+    let mut level = 0usize;
+    while level < reward_percentages.len() {
+        let Some(referrer_id) = referrer_of(tx, current).await? else {
+            break;
+        };
+        if !visited.insert(referrer_id) {
+            break;
+        }
+        current = referrer_id;
+
+        if is_active(tx, referrer_id).await? {
+            level += 1;
+            let amt = percent_of(amount, reward_percentages[level - 1]);
+            if amt > 0
+                && insert_reward(tx, purchase_id, buyer_id, referrer_id, level as i32, amt).await?
+            {
+                add_balance(tx, referrer_id, amt).await?;
+                events.push(Event::RewardGranted {
+                    purchase_id,
+                    beneficiary_user_id: referrer_id,
+                    level: level as i32,
+                    amount: amt,
+                    request_id: request_id.to_string(),
+                });
+            }
         }
     }
 
-    tx.commit().await?;
+    Ok(())
+}
+
+/// Reverses every not-yet-reversed reward for a purchase: subtracts each
+/// reward's amount back from its beneficiary's balance and marks the reward
+/// row as reversed. Safe to call more than once for the same purchase, since
+/// rewards that are already marked reversed are skipped.
+async fn claw_back_rewards(
+    tx: &mut Transaction<'_, Postgres>,
+    events: &mut Vec<Event>,
+    purchase_id: Uuid,
+    request_id: &str,
+) -> Result<()> {
+    let rewards = sqlx::query!(
+        r#"SELECT id, beneficiary_user_id, amount FROM rewards
+           WHERE purchase_id = $1 AND reversed_at IS NULL FOR UPDATE"#,
+        purchase_id
+    )
+    .fetch_all(tx.as_mut())
+    .await?;
+
+    for reward in rewards {
+        add_balance(tx, reward.beneficiary_user_id, -reward.amount).await?;
+        sqlx::query!(
+            r#"UPDATE rewards SET reversed_at = now() WHERE id = $1"#,
+            reward.id
+        )
+        .execute(tx.as_mut())
+        .await?;
+        events.push(Event::RewardReversed {
+            purchase_id,
+            beneficiary_user_id: reward.beneficiary_user_id,
+            amount: reward.amount,
+            request_id: request_id.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Credits a referred buyer's one-time signup bonus the first time one of
+/// their purchases is captured; buyers with no referrer get no bonus. Guarded by a unique constraint on
+/// `referral_bonuses.user_id`: every captured purchase attempts the insert,
+/// but only the first one to commit wins, so the bonus fires at most once
+/// per user even under concurrent processing. The bonus `amount` is recorded
+/// on the `referral_bonuses` row itself, not just applied to the cached
+/// balance, so `balances_v` can reproduce it from the ledger alone.
+async fn grant_signup_bonus(
+    tx: &mut Transaction<'_, Postgres>,
+    events: &mut Vec<Event>,
+    purchase_id: Uuid,
+    buyer_id: i64,
+    bonus: i64,
+    request_id: &str,
+) -> Result<()> {
+    if bonus <= 0 {
+        return Ok(());
+    }
+    if referrer_of(tx, buyer_id).await?.is_none() {
+        return Ok(());
+    }
+
+    let res = sqlx::query!(
+        r#"INSERT INTO referral_bonuses (user_id, purchase_id, amount) VALUES ($1, $2, $3)
+           ON CONFLICT (user_id) DO NOTHING"#,
+        buyer_id,
+        purchase_id,
+        bonus
+    )
+    .execute(tx.as_mut())
+    .await?;
+
+    if res.rows_affected() == 1 {
+        add_balance(tx, buyer_id, bonus).await?;
+        events.push(Event::SignupBonusGranted {
+            purchase_id,
+            user_id: buyer_id,
+            amount: bonus,
+            request_id: request_id.to_string(),
+        });
+    }
+
     Ok(())
 }
 
@@ -79,23 +231,18 @@ pub fn percent_of(amount: i64, percent: i32) -> i64 {
     ((amount as i128 * percent as i128) / 100) as i64
 }
 
-async fn active_referrer(tx: &mut Transaction<'_, Postgres>, user_id: i64) -> Result<Option<i64>> {
+async fn referrer_of(tx: &mut Transaction<'_, Postgres>, user_id: i64) -> Result<Option<i64>> {
     let row = sqlx::query!(r#"SELECT referrer_id FROM users WHERE id = $1"#, user_id)
         .fetch_one(tx.as_mut()) // <- use underlying connection from Transaction
         .await?;
+    Ok(row.referrer_id)
+}
 
-    let referrer_id = row.referrer_id;
-    if let Some(rid) = referrer_id {
-        if let Some(r2) = sqlx::query!(r#"SELECT is_active FROM users WHERE id = $1"#, rid)
-            .fetch_optional(tx.as_mut())
-            .await?
-        {
-            if r2.is_active {
-                return Ok(Some(rid));
-            }
-        }
-    }
-    Ok(None)
+async fn is_active(tx: &mut Transaction<'_, Postgres>, user_id: i64) -> Result<bool> {
+    let row = sqlx::query!(r#"SELECT is_active FROM users WHERE id = $1"#, user_id)
+        .fetch_optional(tx.as_mut())
+        .await?;
+    Ok(row.map(|r| r.is_active).unwrap_or(false))
 }
 
 async fn insert_reward(
@@ -132,3 +279,89 @@ async fn add_balance(tx: &mut Transaction<'_, Postgres>, user_id: i64, delta: i6
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drives several concurrent `process_purchase` calls against the same
+    /// captured purchase and asserts the cached `balances` row ends up equal
+    /// to the ledger-derived `balances_v` row for the referrer who gets paid.
+    #[sqlx::test]
+    async fn concurrent_process_purchase_keeps_cache_and_ledger_in_sync(pool: PgPool) {
+        let referrer_id = 1_i64;
+        let buyer_id = 2_i64;
+
+        sqlx::query!(
+            r#"INSERT INTO users (id, referrer_id, is_active) VALUES ($1, NULL, true)"#,
+            referrer_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"INSERT INTO users (id, referrer_id, is_active) VALUES ($1, $2, true)"#,
+            buyer_id,
+            referrer_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let purchase_id = Uuid::new_v4();
+        sqlx::query!(
+            r#"INSERT INTO purchases (id, user_id, amount, status) VALUES ($1, $2, $3, 'captured')"#,
+            purchase_id,
+            buyer_id,
+            1_000_i64
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let reward_percentages = vec![10_i32];
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let pool = pool.clone();
+                let reward_percentages = reward_percentages.clone();
+                tokio::spawn(async move {
+                    process_purchase(
+                        &pool,
+                        &NoopProducer,
+                        &reward_percentages,
+                        0,
+                        "concurrent-test",
+                        purchase_id,
+                    )
+                    .await
+                    .unwrap();
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let cached = sqlx::query!(
+            r#"SELECT balance FROM balances WHERE user_id = $1"#,
+            referrer_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .balance;
+
+        let ledger = sqlx::query!(
+            r#"SELECT balance AS "balance!" FROM balances_v WHERE user_id = $1"#,
+            referrer_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .balance;
+
+        assert_eq!(cached, ledger);
+        assert_eq!(cached, 100);
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
-use referral_system::{AppState, Config, init_pool, init_router};
+use referral_system::{AppState, Config, NoopProducer, Producer, init_pool, init_router};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -15,9 +16,11 @@ async fn main() -> Result<()> {
 
     let config = Config::from_env()?;
     let pool = init_pool(&config.database_url).await?;
+    let producer = build_producer(&config)?;
     let app = init_router(AppState {
         pool,
         config: config.clone(),
+        producer,
     });
 
     let port = config.server_port;
@@ -28,3 +31,20 @@ async fn main() -> Result<()> {
     axum::serve(listener, app).await?;
     Ok(())
 }
+
+/// Builds the event producer from config: a Kafka producer when both the
+/// broker URL and topic are set and the `kafka` feature is enabled, and a
+/// no-op producer otherwise so the crate still runs without a broker.
+fn build_producer(config: &Config) -> Result<Arc<dyn Producer>> {
+    #[cfg(feature = "kafka")]
+    if let (Some(broker_url), Some(topic)) = (&config.event_broker_url, &config.event_topic) {
+        return Ok(Arc::new(referral_system::KafkaProducer::new(
+            broker_url, topic,
+        )?));
+    }
+
+    #[cfg(not(feature = "kafka"))]
+    let _ = config;
+
+    Ok(Arc::new(NoopProducer))
+}